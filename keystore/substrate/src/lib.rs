@@ -1,33 +1,214 @@
+mod secret_request;
+
+pub use secret_request::{
+    approve_secret_request, import_secret_share, RequestId, RequestKeypair, SecretRequest,
+    SecretRequestError, SecretShare,
+};
+
 use async_trait::async_trait;
 pub use keybase_keystore::{bip39, Error, Mask, NotEnoughEntropyError, Password};
 use keybase_keystore::{bip39::Mnemonic, DeviceKey};
 use sp_core::Pair;
 use sp_runtime::traits::{IdentifyAccount, Verify};
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::marker::PhantomData;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use substrate_subxt::{
     sp_core, sp_runtime, system::System, PairSigner, Runtime, SignedExtension, SignedExtra,
 };
 use sunshine_core::{ChainSigner, InvalidSuri, OffchainSigner, SecretString};
+use tempfile::TempDir;
+
+#[async_trait]
+pub trait KeyStorage: Send + Sync {
+    async fn load(
+        &self,
+        kdf_iterations: Option<u32>,
+    ) -> Result<keybase_keystore::Keystore, Error>;
+    async fn remove(&self) -> Result<(), Error>;
+}
+
+pub struct FilesystemStorage(PathBuf);
+
+impl FilesystemStorage {
+    pub fn new(path: PathBuf) -> Self {
+        Self(path)
+    }
+}
+
+#[async_trait]
+impl KeyStorage for FilesystemStorage {
+    async fn load(
+        &self,
+        kdf_iterations: Option<u32>,
+    ) -> Result<keybase_keystore::Keystore, Error> {
+        Ok(open_keystore(self.0.clone(), kdf_iterations))
+    }
+
+    async fn remove(&self) -> Result<(), Error> {
+        let _ = std::fs::remove_dir_all(&self.0);
+        Ok(())
+    }
+}
+
+/// Still filesystem-backed: `keybase_keystore` has no non-path constructor
+/// to build a real in-memory store against.
+pub struct TempDirStorage(TempDir);
+
+impl TempDirStorage {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self(TempDir::new()?))
+    }
+}
+
+#[async_trait]
+impl KeyStorage for TempDirStorage {
+    async fn load(
+        &self,
+        kdf_iterations: Option<u32>,
+    ) -> Result<keybase_keystore::Keystore, Error> {
+        Ok(open_keystore(self.0.path().to_path_buf(), kdf_iterations))
+    }
+
+    async fn remove(&self) -> Result<(), Error> {
+        // The temp directory is deleted when `self.0` drops.
+        Ok(())
+    }
+}
+
+fn open_keystore(path: PathBuf, kdf_iterations: Option<u32>) -> keybase_keystore::Keystore {
+    match kdf_iterations {
+        Some(kdf_iterations) => keybase_keystore::Keystore::with_kdf_iterations(path, kdf_iterations),
+        None => keybase_keystore::Keystore::new(path),
+    }
+}
 
 pub struct Keystore<T: Runtime, P: Pair<Seed = [u8; 32]>> {
     keystore: keybase_keystore::Keystore,
-    signer: Option<PairSigner<T, P>>,
+    signer: Option<UnlockedSigner<T, P>>,
     gen: u16,
+    accounts: HashMap<T::AccountId, Account<T, P>>,
+    selected: Option<T::AccountId>,
+}
+
+struct Account<T: Runtime, P: Pair<Seed = [u8; 32]>> {
+    keystore: keybase_keystore::Keystore,
+    signer: Option<UnlockedSigner<T, P>>,
+}
+
+enum Unlock {
+    Perm,
+    Temp,
+}
+
+/// Split out of `UnlockedSigner` so the lock/expiry bookkeeping doesn't
+/// depend on `T`/`P` and can be unit-tested on its own.
+struct Expiry {
+    mode: Unlock,
+    valid_until: Option<Instant>,
+}
+
+impl Expiry {
+    fn perm() -> Self {
+        Self {
+            mode: Unlock::Perm,
+            valid_until: None,
+        }
+    }
+
+    fn temp(duration: Option<Duration>) -> Self {
+        Self {
+            mode: Unlock::Temp,
+            valid_until: duration.map(|duration| Instant::now() + duration),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        matches!(self.mode, Unlock::Temp)
+            && matches!(self.valid_until, Some(valid_until) if Instant::now() >= valid_until)
+    }
+}
+
+struct UnlockedSigner<T: Runtime, P: Pair<Seed = [u8; 32]>> {
+    signer: PairSigner<T, P>,
+    expiry: Expiry,
+}
+
+impl<T: Runtime, P: Pair<Seed = [u8; 32]>> UnlockedSigner<T, P> {
+    fn perm(signer: PairSigner<T, P>) -> Self {
+        Self {
+            signer,
+            expiry: Expiry::perm(),
+        }
+    }
+
+    fn temp(signer: PairSigner<T, P>, duration: Option<Duration>) -> Self {
+        Self {
+            signer,
+            expiry: Expiry::temp(duration),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expiry.is_expired()
+    }
+}
+
+#[cfg(test)]
+mod expiry_tests {
+    use super::{Expiry, Unlock};
+    use std::time::Duration;
+
+    #[test]
+    fn perm_never_expires() {
+        assert!(!Expiry::perm().is_expired());
+    }
+
+    #[test]
+    fn temp_without_duration_never_expires() {
+        assert!(!Expiry::temp(None).is_expired());
+    }
+
+    #[test]
+    fn temp_with_duration_expires_once_elapsed() {
+        let expiry = Expiry::temp(Some(Duration::from_millis(0)));
+        assert!(expiry.is_expired());
+    }
+
+    #[test]
+    fn temp_with_future_duration_has_not_expired_yet() {
+        let expiry = Expiry::temp(Some(Duration::from_secs(60)));
+        assert!(!expiry.is_expired());
+    }
+
+    #[test]
+    fn mode_is_preserved() {
+        assert!(matches!(Expiry::perm().mode, Unlock::Perm));
+        assert!(matches!(Expiry::temp(None).mode, Unlock::Temp));
+    }
 }
 
 impl<T: Runtime, P: Pair<Seed = [u8; 32]>> Keystore<T, P>
 where
-    T::AccountId: Into<T::Address>,
+    T::AccountId: Clone + Eq + Hash + Into<T::Address>,
     <<T::Extra as SignedExtra<T>>::Extra as SignedExtension>::AdditionalSigned: Send + Sync,
     T::Signature: From<P::Signature>,
     <T::Signature as Verify>::Signer: From<P::Public> + IdentifyAccount<AccountId = T::AccountId>,
 {
     pub async fn open(path: PathBuf) -> Result<Self, Error> {
-        let keystore = keybase_keystore::Keystore::new(path);
+        Self::with_storage(FilesystemStorage::new(path), None).await
+    }
+
+    pub async fn with_storage(
+        storage: impl KeyStorage,
+        kdf_iterations: Option<u32>,
+    ) -> Result<Self, Error> {
+        let keystore = storage.load(kdf_iterations).await?;
         let gen = keystore.gen().await?;
         let signer = if let Ok(key) = keystore.device_key().await {
-            Some(Key::from_seed(key).to_signer())
+            Some(UnlockedSigner::perm(Key::from_seed(key).to_signer()))
         } else {
             None
         };
@@ -35,14 +216,147 @@ where
             keystore,
             signer,
             gen,
+            accounts: HashMap::new(),
+            selected: None,
         })
     }
+
+    pub async fn add_account(
+        &mut self,
+        path: PathBuf,
+        device_key: &Key<T, P>,
+        password: &SecretString,
+        force: bool,
+        kdf_iterations: Option<u32>,
+    ) -> Result<T::AccountId, Error> {
+        let keystore = open_keystore(path, kdf_iterations);
+        keystore
+            .set_device_key(&device_key.key, &Password::new(password), force)
+            .await?;
+        let signer = device_key.to_signer();
+        let account_id = signer.account_id().clone();
+        self.accounts.insert(
+            account_id.clone(),
+            Account {
+                keystore,
+                signer: Some(UnlockedSigner::perm(signer)),
+            },
+        );
+        Ok(account_id)
+    }
+
+    pub fn remove_account(&mut self, account: &T::AccountId) {
+        self.accounts.remove(account);
+        if self.selected.as_ref() == Some(account) {
+            self.selected = None;
+        }
+    }
+
+    pub fn list_accounts(&self) -> Vec<T::AccountId> {
+        let mut accounts: Vec<_> = self.accounts.keys().cloned().collect();
+        if let Some(signer) = &self.signer {
+            accounts.push(signer.signer.account_id().clone());
+        }
+        accounts
+    }
+
+    /// `false` if `account` is neither the default nor a previously added one.
+    pub fn select_account(&mut self, account: &T::AccountId) -> bool {
+        if self.accounts.contains_key(account)
+            || self.signer.as_ref().map(|s| s.signer.account_id()) == Some(account)
+        {
+            self.selected = Some(account.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub async fn unlock_temp(
+        &mut self,
+        password: &SecretString,
+        duration: Option<Duration>,
+    ) -> Result<(), Error> {
+        let key = Key::from_seed(self.keystore.unlock(&Password::new(password)).await?);
+        self.signer = Some(UnlockedSigner::temp(key.to_signer(), duration));
+        Ok(())
+    }
+
+    pub async fn prune_expired(&mut self) -> Result<(), Error> {
+        if self.signer.as_ref().map_or(false, UnlockedSigner::is_expired) {
+            self.signer = None;
+            self.keystore.lock().await?;
+        }
+        Ok(())
+    }
+
+    /// For the methods that talk to `self.keystore` directly instead of
+    /// going through `current_signer()`'s own expiry check.
+    async fn relock_if_expired(&self) -> Result<(), Error> {
+        if self.signer.as_ref().map_or(false, UnlockedSigner::is_expired) {
+            self.keystore.lock().await?;
+        }
+        Ok(())
+    }
+
+    /// Zeroizing counterpart to `sunshine_core::Keystore::password`.
+    pub async fn password_secret(&self) -> Result<(Password, u16), Error> {
+        self.relock_if_expired().await?;
+        self.keystore.password().await
+    }
+
+    /// Zeroizing counterpart to `sunshine_core::Keystore::change_password_mask`.
+    pub async fn change_password_mask_secret(
+        &self,
+        password: &SecretString,
+    ) -> Result<(Mask, u16), Error> {
+        self.relock_if_expired().await?;
+        self.keystore
+            .change_password_mask(&Password::new(password))
+            .await
+    }
+
+    /// Zeroizing counterpart to `sunshine_core::Keystore::apply_mask`.
+    pub async fn apply_mask_secret(&mut self, mask: &Mask, next_gen: u16) -> Result<(), Error> {
+        self.relock_if_expired().await?;
+        self.keystore.apply_mask(mask, next_gen).await?;
+        self.gen = next_gen;
+        Ok(())
+    }
+
+    /// Zeroizing counterpart to `sunshine_core::Keystore::provision_device`.
+    pub async fn provision_device_secret(
+        &mut self,
+        password: &Password,
+        gen: u16,
+    ) -> Result<T::AccountId, Error> {
+        let device_key = self.keystore.provision_device_key(password, gen).await?;
+        let key = Key::from_seed(device_key);
+        self.signer = Some(UnlockedSigner::perm(key.to_signer()));
+        Ok(self.current_signer().unwrap().account_id().clone())
+    }
+
+    fn current_signer(&self) -> Option<&PairSigner<T, P>> {
+        if let Some(account) = self.selected.as_ref().and_then(|a| self.accounts.get(a)) {
+            return match account.signer.as_ref() {
+                Some(signer) if !signer.is_expired() => Some(&signer.signer),
+                _ => None,
+            };
+        }
+        match &self.signer {
+            Some(signer) if !signer.is_expired() => Some(&signer.signer),
+            _ => None,
+        }
+    }
 }
 
+/// The raw `[u8; 32]` types this trait is fixed to can't be zeroized here;
+/// callers holding this concrete type should prefer the `_secret` methods
+/// above instead of going through `dyn sunshine_core::Keystore`.
 #[async_trait]
 impl<T: Runtime, P: Pair<Seed = [u8; 32]>> sunshine_core::Keystore<T> for Keystore<T, P>
 where
-    T::AccountId: Clone + Into<T::Address>,
+    T::AccountId: Clone + Eq + Hash + Into<T::Address>,
     <<T::Extra as SignedExtra<T>>::Extra as SignedExtension>::AdditionalSigned: Send + Sync,
     T::Signature: From<P::Signature>,
     <T::Signature as Verify>::Signer: From<P::Public> + IdentifyAccount<AccountId = T::AccountId>,
@@ -51,11 +365,11 @@ where
     type Error = Error;
 
     fn chain_signer(&self) -> Option<&(dyn ChainSigner<T> + Send + Sync)> {
-        self.signer.as_ref().map(|s| s as _)
+        self.current_signer().map(|s| s as _)
     }
 
     fn offchain_signer(&self) -> Option<&dyn OffchainSigner<T>> {
-        self.signer.as_ref().map(|s| s as _)
+        self.current_signer().map(|s| s as _)
     }
 
     async fn set_device_key(
@@ -67,13 +381,13 @@ where
         self.keystore
             .set_device_key(&device_key.key, &Password::new(password), force)
             .await?;
-        self.signer = Some(device_key.to_signer());
+        self.signer = Some(UnlockedSigner::perm(device_key.to_signer()));
         Ok(())
     }
 
     async fn password(&self) -> Result<([u8; 32], u16), Self::Error> {
+        self.relock_if_expired().await?;
         let (password, gen) = self.keystore.password().await?;
-        // TODO: not
         Ok((*password.expose_secret(), gen))
     }
 
@@ -82,21 +396,28 @@ where
         password: &[u8; 32],
         gen: u16,
     ) -> Result<T::AccountId, Error> {
-        let password = Password::from(*password);
-        let device_key = self.keystore.provision_device_key(&password, gen).await?;
+        let device_key = self
+            .keystore
+            .provision_device_key(&Password::from(*password), gen)
+            .await?;
         let key = Key::from_seed(device_key);
-        self.signer = Some(key.to_signer());
+        self.signer = Some(UnlockedSigner::perm(key.to_signer()));
         Ok(self.chain_signer().unwrap().account_id().clone())
     }
 
     async fn lock(&mut self) -> Result<(), Self::Error> {
         self.signer = None;
-        self.keystore.lock().await
+        self.keystore.lock().await?;
+        for account in self.accounts.values_mut() {
+            account.signer = None;
+            account.keystore.lock().await?;
+        }
+        Ok(())
     }
 
     async fn unlock(&mut self, password: &SecretString) -> Result<(), Self::Error> {
         let key = Key::from_seed(self.keystore.unlock(&Password::new(password)).await?);
-        self.signer = Some(key.to_signer());
+        self.signer = Some(UnlockedSigner::perm(key.to_signer()));
         Ok(())
     }
 
@@ -108,16 +429,17 @@ where
         &self,
         password: &SecretString,
     ) -> Result<([u8; 32], u16), Self::Error> {
+        self.relock_if_expired().await?;
         let (mask, gen) = self
             .keystore
             .change_password_mask(&Password::new(password))
             .await?;
-        Ok((*mask, gen))
+        Ok((*mask.expose_secret(), gen))
     }
 
     async fn apply_mask(&mut self, mask: &[u8; 32], next_gen: u16) -> Result<(), Self::Error> {
-        let mask = Mask::new(*mask);
-        self.keystore.apply_mask(&mask, next_gen).await?;
+        self.relock_if_expired().await?;
+        self.keystore.apply_mask(&Mask::from(*mask), next_gen).await?;
         self.gen = next_gen;
         Ok(())
     }
@@ -0,0 +1,292 @@
+//! Cross-device secret request/import protocol for provisioning. A new
+//! device publishes a `SecretRequest`; a trusted device answers with a
+//! `SecretShare` sealed to the requester's public key.
+
+use crate::{Error, Keystore, Password};
+use crypto_box::{PublicKey, SalsaBox, SecretKey};
+use parity_scale_codec::{Decode, Encode};
+use rand::rngs::OsRng;
+use sp_core::Pair;
+use sp_runtime::traits::{IdentifyAccount, Verify};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use substrate_subxt::{Runtime, SignedExtension, SignedExtra};
+use thiserror::Error as ThisError;
+use zeroize::Zeroize;
+
+/// Identifies one provisioning handshake. It is echoed back in
+/// `SecretShare::request_id` and compared by `import_secret_share`, so a
+/// share answering a different request is rejected; the id travels outside
+/// the sealed payload and is not authenticated by it.
+pub type RequestId = [u8; 16];
+
+#[derive(Debug, ThisError)]
+pub enum SecretRequestError {
+    #[error(transparent)]
+    Keystore(#[from] Error),
+    #[error("Secret request's device fingerprint wasn't confirmed out-of-band.")]
+    FingerprintMismatch,
+    #[error("Secret share's request id doesn't match the outstanding request.")]
+    RequestIdMismatch,
+    #[error("Secret share isn't bound to the requesting account.")]
+    AccountMismatch,
+    #[error("Failed to decrypt secret share.")]
+    Decryption,
+}
+
+pub struct RequestKeypair {
+    secret: SecretKey,
+    public: PublicKey,
+}
+
+impl RequestKeypair {
+    pub fn generate() -> Self {
+        let secret = SecretKey::generate(&mut OsRng);
+        let public = secret.public_key();
+        Self { secret, public }
+    }
+
+    pub fn request<AccountId>(
+        &self,
+        requesting_account: AccountId,
+        request_id: RequestId,
+    ) -> SecretRequest<AccountId> {
+        SecretRequest {
+            requesting_account,
+            request_id,
+            device_pubkey: self.public,
+        }
+    }
+}
+
+/// Published by the new device. Carries nothing secret, but nothing here
+/// authenticates `device_pubkey` — `approve_secret_request` requires its
+/// `fingerprint()` to be confirmed out-of-band before sealing to it.
+pub struct SecretRequest<AccountId> {
+    pub requesting_account: AccountId,
+    pub request_id: RequestId,
+    pub device_pubkey: PublicKey,
+}
+
+impl<AccountId> SecretRequest<AccountId> {
+    /// Human-comparable fingerprint of `device_pubkey`.
+    pub fn fingerprint(&self) -> String {
+        self.device_pubkey
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+pub struct SecretShare {
+    pub request_id: RequestId,
+    sealed: Vec<u8>,
+}
+
+/// Called on an already-unlocked trusted device to answer a
+/// `SecretRequest`. `confirmed_fingerprint` must equal
+/// `request.fingerprint()`, confirmed out-of-band by the operator.
+pub async fn approve_secret_request<T, P, AccountId>(
+    keystore: &Keystore<T, P>,
+    request: &SecretRequest<AccountId>,
+    confirmed_fingerprint: &str,
+) -> Result<SecretShare, SecretRequestError>
+where
+    T: Runtime,
+    P: Pair<Seed = [u8; 32]>,
+    AccountId: Encode,
+    T::AccountId: Clone + Eq + Hash + Into<T::Address>,
+    <<T::Extra as SignedExtra<T>>::Extra as SignedExtension>::AdditionalSigned: Send + Sync,
+    T::Signature: From<P::Signature>,
+    <T::Signature as Verify>::Signer: From<P::Public> + IdentifyAccount<AccountId = T::AccountId>,
+{
+    if confirmed_fingerprint != request.fingerprint() {
+        return Err(SecretRequestError::FingerprintMismatch);
+    }
+    let (password, gen) = keystore.password_secret().await?;
+    let mut payload = build_payload(&request.requesting_account, &password, gen);
+    let sealed = seal(&request.device_pubkey, &payload);
+    payload.zeroize();
+    Ok(SecretShare {
+        request_id: request.request_id,
+        sealed,
+    })
+}
+
+pub async fn import_secret_share<T, P>(
+    keystore: &mut Keystore<T, P>,
+    requesting_account: &T::AccountId,
+    request_id: RequestId,
+    request_keypair: &RequestKeypair,
+    share: SecretShare,
+) -> Result<T::AccountId, SecretRequestError>
+where
+    T: Runtime,
+    P: Pair<Seed = [u8; 32]>,
+    T::AccountId: Decode + Clone + Eq + Hash + Into<T::Address>,
+    <<T::Extra as SignedExtra<T>>::Extra as SignedExtension>::AdditionalSigned: Send + Sync,
+    T::Signature: From<P::Signature>,
+    <T::Signature as Verify>::Signer: From<P::Public> + IdentifyAccount<AccountId = T::AccountId>,
+{
+    let (password, gen) = verify_and_decode(requesting_account, request_id, &share, request_keypair)?;
+    Ok(keystore.provision_device_secret(&password, gen).await?)
+}
+
+/// Pulled out of `import_secret_share` so it's testable without a
+/// concrete `Runtime`/`Pair`/`Keystore`.
+fn verify_and_decode<AccountId: Decode + PartialEq>(
+    requesting_account: &AccountId,
+    request_id: RequestId,
+    share: &SecretShare,
+    request_keypair: &RequestKeypair,
+) -> Result<(Password, u16), SecretRequestError> {
+    if share.request_id != request_id {
+        return Err(SecretRequestError::RequestIdMismatch);
+    }
+    let mut payload = unseal(&request_keypair.secret, &share.sealed)
+        .map_err(|_| SecretRequestError::Decryption)?;
+    let mut input = &payload[..];
+    let account = AccountId::decode(&mut input).map_err(|_| SecretRequestError::Decryption)?;
+    if &account != requesting_account {
+        payload.zeroize();
+        return Err(SecretRequestError::AccountMismatch);
+    }
+    if input.len() < 34 {
+        payload.zeroize();
+        return Err(SecretRequestError::Decryption);
+    }
+    let mut password = [0u8; 32];
+    password.copy_from_slice(&input[..32]);
+    let gen = u16::from_le_bytes([input[32], input[33]]);
+    payload.zeroize();
+    let secret = Password::from(password);
+    password.zeroize();
+    Ok((secret, gen))
+}
+
+/// The plaintext sealed inside a `SecretShare`: the requesting account,
+/// the password, and the keystore generation it belongs to.
+fn build_payload<AccountId: Encode>(account: &AccountId, password: &Password, gen: u16) -> Vec<u8> {
+    let mut payload = account.encode();
+    payload.extend_from_slice(password.expose_secret());
+    payload.extend_from_slice(&gen.to_le_bytes());
+    payload
+}
+
+fn seal(recipient: &PublicKey, plaintext: &[u8]) -> Vec<u8> {
+    let ephemeral_secret = SecretKey::generate(&mut OsRng);
+    let ephemeral_public = ephemeral_secret.public_key();
+    let nonce = crypto_box::generate_nonce(&mut OsRng);
+    let b = SalsaBox::new(recipient, &ephemeral_secret);
+    let ciphertext = b
+        .encrypt(&nonce, plaintext)
+        .expect("encrypting an in-memory payload never fails");
+    let mut sealed = Vec::with_capacity(32 + 24 + ciphertext.len());
+    sealed.extend_from_slice(ephemeral_public.as_bytes());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+fn unseal(secret: &SecretKey, sealed: &[u8]) -> Result<Vec<u8>, ()> {
+    if sealed.len() < 32 + 24 {
+        return Err(());
+    }
+    let (ephemeral_public, rest) = sealed.split_at(32);
+    let (nonce, ciphertext) = rest.split_at(24);
+    let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(ephemeral_public).unwrap());
+    let nonce = crypto_box::Nonce::from_slice(nonce);
+    let b = SalsaBox::new(&ephemeral_public, secret);
+    b.decrypt(nonce, ciphertext).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_unseal_round_trip() {
+        let recipient = SecretKey::generate(&mut OsRng);
+        let plaintext = b"super secret password bytes....".to_vec();
+        let sealed = seal(&recipient.public_key(), &plaintext);
+        assert_eq!(unseal(&recipient, &sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn unseal_rejects_tampered_ciphertext() {
+        let recipient = SecretKey::generate(&mut OsRng);
+        let mut sealed = seal(&recipient.public_key(), b"secret");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+        assert!(unseal(&recipient, &sealed).is_err());
+    }
+
+    #[test]
+    fn unseal_rejects_truncated_input() {
+        let recipient = SecretKey::generate(&mut OsRng);
+        assert!(unseal(&recipient, &[0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn fingerprint_is_stable_hex_of_device_pubkey() {
+        let keypair = RequestKeypair::generate();
+        let request = keypair.request(7u64, [1u8; 16]);
+        let fingerprint = request.fingerprint();
+        assert_eq!(fingerprint.len(), 64);
+        assert_eq!(fingerprint, request.fingerprint());
+    }
+
+    fn well_formed_share(
+        keypair: &RequestKeypair,
+        account: u64,
+        request_id: RequestId,
+        password: [u8; 32],
+        gen: u16,
+    ) -> SecretShare {
+        let payload = build_payload(&account, &Password::from(password), gen);
+        SecretShare {
+            request_id,
+            sealed: seal(&keypair.public, &payload),
+        }
+    }
+
+    #[test]
+    fn verify_and_decode_accepts_well_formed_share() {
+        let keypair = RequestKeypair::generate();
+        let password = [9u8; 32];
+        let share = well_formed_share(&keypair, 7u64, [1u8; 16], password, 3);
+        let (decoded, gen) = verify_and_decode(&7u64, [1u8; 16], &share, &keypair).unwrap();
+        assert_eq!(decoded.expose_secret(), &password);
+        assert_eq!(gen, 3);
+    }
+
+    #[test]
+    fn verify_and_decode_rejects_wrong_request_id() {
+        let keypair = RequestKeypair::generate();
+        let share = well_formed_share(&keypair, 7u64, [1u8; 16], [9u8; 32], 3);
+        let err = verify_and_decode(&7u64, [2u8; 16], &share, &keypair).unwrap_err();
+        assert!(matches!(err, SecretRequestError::RequestIdMismatch));
+    }
+
+    #[test]
+    fn verify_and_decode_rejects_wrong_account() {
+        let keypair = RequestKeypair::generate();
+        let share = well_formed_share(&keypair, 7u64, [1u8; 16], [9u8; 32], 3);
+        let err = verify_and_decode(&8u64, [1u8; 16], &share, &keypair).unwrap_err();
+        assert!(matches!(err, SecretRequestError::AccountMismatch));
+    }
+
+    #[test]
+    fn verify_and_decode_rejects_share_sealed_to_a_different_key() {
+        let keypair = RequestKeypair::generate();
+        let other = RequestKeypair::generate();
+        let payload = build_payload(&7u64, &Password::from([9u8; 32]), 3);
+        let share = SecretShare {
+            request_id: [1u8; 16],
+            sealed: seal(&other.public, &payload),
+        };
+        let err = verify_and_decode(&7u64, [1u8; 16], &share, &keypair).unwrap_err();
+        assert!(matches!(err, SecretRequestError::Decryption));
+    }
+}